@@ -7,30 +7,78 @@ use revault_tx::bitcoin::{
 };
 
 use std::{
-    process,
     str::FromStr,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         mpsc::{self, Sender},
         Arc,
     },
 };
 
-use jsonrpc_core::Error as JsonRpcError;
+use futures::{channel::oneshot, FutureExt};
+use jsonrpc_core::{BoxFuture, Error as JsonRpcError};
 use jsonrpc_derive::rpc;
+use jsonrpc_pubsub::{typed::Subscriber, PubSubMetadata, Session, SubscriptionId};
 use serde_json::json;
 
+/// Filter applied to vault subscriptions, mirroring `listvaults`' parameters: a subscriber only
+/// gets notified of a vault's status transitions if it matches both (when set).
+#[derive(Clone)]
+pub struct VaultSubscriptionFilter {
+    pub status: Option<VaultStatus>,
+    pub txids: Option<Vec<Txid>>,
+}
+
+/// Which of a vault's presigned transactions to broadcast.
+#[derive(Clone, Copy)]
+pub enum PresignedTransactionKind {
+    Cancel,
+    Emergency,
+    Unvault,
+    Spend,
+}
+
+impl FromStr for PresignedTransactionKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cancel" => Ok(Self::Cancel),
+            "emergency" => Ok(Self::Emergency),
+            "unvault" => Ok(Self::Unvault),
+            "spend" => Ok(Self::Spend),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct JsonRpcMetaData {
     pub tx: Sender<ThreadMessageIn>,
+    pub session: Option<Arc<Session>>,
     pub shutdown: Arc<AtomicBool>,
 }
 impl jsonrpc_core::Metadata for JsonRpcMetaData {}
 
+impl PubSubMetadata for JsonRpcMetaData {
+    fn session(&self) -> Option<Arc<Session>> {
+        self.session.clone()
+    }
+}
+
 impl JsonRpcMetaData {
     pub fn from_tx(tx: Sender<ThreadMessageIn>) -> Self {
         JsonRpcMetaData {
             tx,
+            session: None,
+            shutdown: Arc::from(AtomicBool::from(false)),
+        }
+    }
+
+    pub fn from_tx_session(tx: Sender<ThreadMessageIn>, session: Arc<Session>) -> Self {
+        JsonRpcMetaData {
+            tx,
+            session: Some(session),
             shutdown: Arc::from(AtomicBool::from(false)),
         }
     }
@@ -55,7 +103,7 @@ pub trait RpcApi {
 
     /// Get informations about the daemon
     #[rpc(meta, name = "getinfo")]
-    fn getinfo(&self, meta: Self::Metadata) -> jsonrpc_core::Result<serde_json::Value>;
+    fn getinfo(&self, meta: Self::Metadata) -> BoxFuture<jsonrpc_core::Result<serde_json::Value>>;
 
     /// Get a list of current vaults, which can be sorted by txids or status
     #[rpc(meta, name = "listvaults")]
@@ -64,10 +112,77 @@ pub trait RpcApi {
         meta: Self::Metadata,
         status: Option<String>,
         txids: Option<Vec<String>>,
-    ) -> jsonrpc_core::Result<serde_json::Value>;
+    ) -> BoxFuture<jsonrpc_core::Result<serde_json::Value>>;
+
+    /// Subscribe to vault status transitions (e.g. Funded -> Secured -> Active -> Spending),
+    /// optionally filtered by status and/or txids like `listvaults`. Each transition is pushed
+    /// as a notification carrying the vault's txid, its previous status and its new status.
+    #[pubsub(subscription = "vaults", subscribe, name = "subscribe_vaults")]
+    fn subscribe_vaults(
+        &self,
+        meta: Self::Metadata,
+        subscriber: Subscriber<serde_json::Value>,
+        status: Option<String>,
+        txids: Option<Vec<String>>,
+    );
+
+    /// Cancel a subscription created by `subscribe_vaults`.
+    #[pubsub(subscription = "vaults", unsubscribe, name = "unsubscribe_vaults")]
+    fn unsubscribe_vaults(
+        &self,
+        meta: Option<Self::Metadata>,
+        id: SubscriptionId,
+    ) -> jsonrpc_core::Result<bool>;
+
+    /// Broadcast one of a vault's presigned transactions ("cancel", "emergency", "unvault" or
+    /// "spend") and immediately check the mempool for its acceptance, returning whether it was
+    /// accepted along with its fee, virtual size, and ancestor/descendant fee totals.
+    #[rpc(meta, name = "broadcast")]
+    fn broadcast(
+        &self,
+        meta: Self::Metadata,
+        vault_txid: String,
+        kind: String,
+    ) -> BoxFuture<jsonrpc_core::Result<serde_json::Value>>;
+
+    /// Get the current set of transactions (deposit, unvault, cancel, emergency, spend, ...)
+    /// known for the vault identified by its deposit txid.
+    #[rpc(meta, name = "getvaulttransactions")]
+    fn getvaulttransactions(
+        &self,
+        meta: Self::Metadata,
+        vault_txid: String,
+    ) -> BoxFuture<jsonrpc_core::Result<serde_json::Value>>;
+
+    /// Get a short feerate history to size spend transactions and verify that presigned
+    /// cancel/emergency transactions still pay enough to confirm. Reports the base feerate and
+    /// low/median/high percentiles for each of the last `block_count` confirmed blocks, plus
+    /// bitcoind's current `estimatesmartfee` targets.
+    #[rpc(meta, name = "getfeerate")]
+    fn getfeerate(
+        &self,
+        meta: Self::Metadata,
+        block_count: usize,
+    ) -> BoxFuture<jsonrpc_core::Result<serde_json::Value>>;
+}
+
+pub struct RpcImpl {
+    /// Used to reach the main thread from contexts that don't carry their own
+    /// `JsonRpcMetaData`, e.g. `unsubscribe_vaults` on client disconnect (`meta` is `None`
+    /// there), where the main thread still needs telling to drop the subscriber's sink.
+    tx: Sender<ThreadMessageIn>,
+    next_subscriber_id: AtomicU64,
+}
+
+impl RpcImpl {
+    pub fn new(tx: Sender<ThreadMessageIn>) -> Self {
+        RpcImpl {
+            tx,
+            next_subscriber_id: AtomicU64::new(0),
+        }
+    }
 }
 
-pub struct RpcImpl;
 impl RpcApi for RpcImpl {
     type Metadata = JsonRpcMetaData;
 
@@ -79,24 +194,28 @@ impl RpcApi for RpcImpl {
         Ok(())
     }
 
-    fn getinfo(&self, meta: Self::Metadata) -> jsonrpc_core::Result<serde_json::Value> {
-        let (response_tx, response_rx) = mpsc::sync_channel(0);
-        meta.tx
+    fn getinfo(&self, meta: Self::Metadata) -> BoxFuture<jsonrpc_core::Result<serde_json::Value>> {
+        let (response_tx, response_rx) = oneshot::channel();
+        if let Err(e) = meta
+            .tx
             .send(ThreadMessageIn::Rpc(RpcMessageIn::GetInfo(response_tx)))
-            .unwrap_or_else(|e| {
-                log::error!("Sending 'getinfo' to main thread: {:?}", e);
-                process::exit(1);
-            });
-        let (net, height, progress) = response_rx.recv().unwrap_or_else(|e| {
-            log::error!("Receiving 'getinfo' result from main thread: {:?}", e);
-            process::exit(1);
-        });
-
-        Ok(json!({
-            "version": VERSION.to_string(),
-            "network": net,
-            "blockheight": height,
-            "sync": progress,
+        {
+            log::error!("Sending 'getinfo' to main thread: {:?}", e);
+            return Box::pin(async { Err(main_thread_unreachable_error()) });
+        }
+
+        Box::pin(response_rx.map(|res| {
+            let (net, height, progress) = res.map_err(|e| {
+                log::error!("Receiving 'getinfo' result from main thread: {:?}", e);
+                main_thread_unreachable_error()
+            })?;
+
+            Ok(json!({
+                "version": VERSION.to_string(),
+                "network": net,
+                "blockheight": height,
+                "sync": progress,
+            }))
         }))
     }
 
@@ -105,48 +224,237 @@ impl RpcApi for RpcImpl {
         meta: Self::Metadata,
         status: Option<String>,
         txids: Option<Vec<String>>,
-    ) -> jsonrpc_core::Result<serde_json::Value> {
-        let status = if let Some(status) = status {
-            Some(VaultStatus::from_str(&status).map_err(|_| {
-                JsonRpcError::invalid_params(format!("'{}' is not a valid vault status", &status))
-            })?)
-        } else {
-            None
+    ) -> BoxFuture<jsonrpc_core::Result<serde_json::Value>> {
+        let (status, txids) = match parse_status(status).and_then(|status| {
+            parse_txids(txids).map(|txids| (status, txids))
+        }) {
+            Ok(parsed) => parsed,
+            Err(e) => return Box::pin(async { Err(e) }),
         };
-        let txids = if let Some(txids) = txids {
-            Some(
-                txids
-                    .into_iter()
-                    .map(|tx_str| {
-                        Txid::from_hex(&tx_str).map_err(|e| {
-                            JsonRpcError::invalid_params(format!(
-                                "'{}' is not a valid txid ({})",
-                                &tx_str,
-                                e.to_string()
-                            ))
-                        })
-                    })
-                    .collect::<jsonrpc_core::Result<Vec<Txid>>>()?,
-            )
-        } else {
-            None
+
+        let (response_tx, response_rx) = oneshot::channel();
+        if let Err(e) = meta.tx.send(ThreadMessageIn::Rpc(RpcMessageIn::ListVaults(
+            (status, txids),
+            response_tx,
+        ))) {
+            log::error!("Sending 'listvaults' to main thread: {:?}", e);
+            return Box::pin(async { Err(main_thread_unreachable_error()) });
+        }
+
+        Box::pin(response_rx.map(|res| {
+            let vaults = res.map_err(|e| {
+                log::error!("Receiving 'listvaults' result from main thread: {:?}", e);
+                main_thread_unreachable_error()
+            })?;
+
+            Ok(json!({ "vaults": vaults }))
+        }))
+    }
+
+    fn subscribe_vaults(
+        &self,
+        meta: Self::Metadata,
+        subscriber: Subscriber<serde_json::Value>,
+        status: Option<String>,
+        txids: Option<Vec<String>>,
+    ) {
+        let filter = match parse_status(status).and_then(|status| {
+            parse_txids(txids).map(|txids| VaultSubscriptionFilter { status, txids })
+        }) {
+            Ok(filter) => filter,
+            Err(e) => {
+                let _ = subscriber.reject(e);
+                return;
+            }
+        };
+
+        let id = self.next_subscriber_id.fetch_add(1, Ordering::Relaxed);
+        let sub_id = SubscriptionId::Number(id);
+        let sink = match subscriber.assign_id(sub_id) {
+            Ok(sink) => sink,
+            Err(_) => return,
         };
 
+        if let Err(e) = meta.tx.send(ThreadMessageIn::Rpc(RpcMessageIn::Subscribe(
+            filter, id, sink,
+        ))) {
+            log::error!("Sending 'subscribe_vaults' to main thread: {:?}", e);
+        }
+    }
+
+    fn unsubscribe_vaults(
+        &self,
+        meta: Option<Self::Metadata>,
+        id: SubscriptionId,
+    ) -> jsonrpc_core::Result<bool> {
+        let id = match id {
+            SubscriptionId::Number(id) => id,
+            SubscriptionId::String(_) => return Ok(false),
+        };
+
+        // `meta` is `None` when this is called by the pubsub `Session` on client disconnect, so
+        // always go through `self.tx` (rather than `meta`'s) to make sure the main thread -
+        // which holds the real sink handed over in `Subscribe` above - is told to drop it
+        // either way. We still need the removal result to answer truthfully, and the pubsub
+        // unsubscribe callback is synchronous (unlike the `BoxFuture` handlers above), so this
+        // is a blocking round-trip rather than a `oneshot`.
         let (response_tx, response_rx) = mpsc::sync_channel(0);
-        meta.tx
-            .send(ThreadMessageIn::Rpc(RpcMessageIn::ListVaults(
-                (status, txids),
-                response_tx,
-            )))
-            .unwrap_or_else(|e| {
-                log::error!("Sending 'listvaults' to main thread: {:?}", e);
-                process::exit(1);
+        if let Err(e) = self.tx.send(ThreadMessageIn::Rpc(RpcMessageIn::Unsubscribe(
+            id,
+            response_tx,
+        ))) {
+            log::error!("Sending 'unsubscribe_vaults' to main thread: {:?}", e);
+            return if meta.is_some() {
+                Err(main_thread_unreachable_error())
+            } else {
+                Ok(false)
+            };
+        }
+
+        Ok(response_rx.recv().unwrap_or_else(|e| {
+            log::error!("Receiving 'unsubscribe_vaults' result from main thread: {:?}", e);
+            false
+        }))
+    }
+
+    fn broadcast(
+        &self,
+        meta: Self::Metadata,
+        vault_txid: String,
+        kind: String,
+    ) -> BoxFuture<jsonrpc_core::Result<serde_json::Value>> {
+        let txid = match parse_txid(&vault_txid) {
+            Ok(txid) => txid,
+            Err(e) => return Box::pin(async { Err(e) }),
+        };
+        let kind = match PresignedTransactionKind::from_str(&kind) {
+            Ok(kind) => kind,
+            Err(_) => {
+                return Box::pin(async move {
+                    Err(JsonRpcError::invalid_params(format!(
+                        "'{}' is not a valid transaction kind (expected one of 'cancel', \
+                         'emergency', 'unvault', 'spend')",
+                        kind
+                    )))
+                })
+            }
+        };
+
+        let (response_tx, response_rx) = oneshot::channel();
+        if let Err(e) = meta.tx.send(ThreadMessageIn::Rpc(RpcMessageIn::Broadcast(
+            txid,
+            kind,
+            response_tx,
+        ))) {
+            log::error!("Sending 'broadcast' to main thread: {:?}", e);
+            return Box::pin(async { Err(main_thread_unreachable_error()) });
+        }
+
+        Box::pin(response_rx.map(|res| {
+            res.map_err(|e| {
+                log::error!("Receiving 'broadcast' result from main thread: {:?}", e);
+                main_thread_unreachable_error()
+            })
+        }))
+    }
+
+    fn getvaulttransactions(
+        &self,
+        meta: Self::Metadata,
+        vault_txid: String,
+    ) -> BoxFuture<jsonrpc_core::Result<serde_json::Value>> {
+        let txid = match parse_txid(&vault_txid) {
+            Ok(txid) => txid,
+            Err(e) => return Box::pin(async { Err(e) }),
+        };
+
+        let (response_tx, response_rx) = oneshot::channel();
+        if let Err(e) = meta.tx.send(ThreadMessageIn::Rpc(
+            RpcMessageIn::GetVaultTransactions(txid, response_tx),
+        )) {
+            log::error!("Sending 'getvaulttransactions' to main thread: {:?}", e);
+            return Box::pin(async { Err(main_thread_unreachable_error()) });
+        }
+
+        Box::pin(response_rx.map(|res| {
+            res.map_err(|e| {
+                log::error!(
+                    "Receiving 'getvaulttransactions' result from main thread: {:?}",
+                    e
+                );
+                main_thread_unreachable_error()
+            })
+        }))
+    }
+
+    fn getfeerate(
+        &self,
+        meta: Self::Metadata,
+        block_count: usize,
+    ) -> BoxFuture<jsonrpc_core::Result<serde_json::Value>> {
+        if block_count == 0 {
+            return Box::pin(async {
+                Err(JsonRpcError::invalid_params(
+                    "'block_count' must be at least 1",
+                ))
             });
-        let vaults = response_rx.recv().unwrap_or_else(|e| {
-            log::error!("Receiving 'listvaults' result from main thread: {:?}", e);
-            process::exit(1);
-        });
+        }
 
-        Ok(json!({ "vaults": vaults }))
+        let (response_tx, response_rx) = oneshot::channel();
+        if let Err(e) = meta.tx.send(ThreadMessageIn::Rpc(RpcMessageIn::GetFeerate(
+            block_count,
+            response_tx,
+        ))) {
+            log::error!("Sending 'getfeerate' to main thread: {:?}", e);
+            return Box::pin(async { Err(main_thread_unreachable_error()) });
+        }
+
+        Box::pin(response_rx.map(|res| {
+            res.map_err(|e| {
+                log::error!("Receiving 'getfeerate' result from main thread: {:?}", e);
+                main_thread_unreachable_error()
+            })
+        }))
     }
-}
\ No newline at end of file
+}
+
+/// The main thread dropped the other end of the channel, which only happens when it has
+/// stopped running; there is no sensible response to give the client other than an error.
+fn main_thread_unreachable_error() -> JsonRpcError {
+    JsonRpcError::internal_error()
+}
+
+fn parse_status(status: Option<String>) -> jsonrpc_core::Result<Option<VaultStatus>> {
+    status
+        .map(|status| {
+            VaultStatus::from_str(&status).map_err(|_| {
+                JsonRpcError::invalid_params(format!("'{}' is not a valid vault status", &status))
+            })
+        })
+        .transpose()
+}
+
+fn parse_txid(txid: &str) -> jsonrpc_core::Result<Txid> {
+    Txid::from_hex(txid).map_err(|e| {
+        JsonRpcError::invalid_params(format!("'{}' is not a valid txid ({})", txid, e.to_string()))
+    })
+}
+
+fn parse_txids(txids: Option<Vec<String>>) -> jsonrpc_core::Result<Option<Vec<Txid>>> {
+    txids
+        .map(|txids| {
+            txids
+                .into_iter()
+                .map(|tx_str| {
+                    Txid::from_hex(&tx_str).map_err(|e| {
+                        JsonRpcError::invalid_params(format!(
+                            "'{}' is not a valid txid ({})",
+                            &tx_str,
+                            e.to_string()
+                        ))
+                    })
+                })
+                .collect::<jsonrpc_core::Result<Vec<Txid>>>()
+        })
+        .transpose()
+}
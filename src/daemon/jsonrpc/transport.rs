@@ -0,0 +1,187 @@
+//! A framed JSON-RPC transport, for embedding revaultd as a child process.
+//!
+//! Unlike the HTTP/line-delimited listeners, this transport speaks
+//! `Content-Length`-prefixed messages (a header block terminated by `\r\n\r\n`, followed by
+//! exactly that many body bytes), so it is robust to pretty-printed JSON and safe to run over a
+//! child process' stdin/stdout or a length-delimited Unix socket. A reader task parses incoming
+//! frames and forwards them to the handler, a writer task serializes outgoing responses and
+//! notifications with the correct header, and the two are decoupled by a channel so that
+//! pub/sub notifications (pushed from the main thread, see `threadmessages::RpcMessageIn::Subscribe`) can
+//! be interleaved with request/response frames rather than waiting behind them.
+
+use crate::jsonrpc::api::JsonRpcMetaData;
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    sync::{mpsc, Arc},
+    thread::{self, JoinHandle},
+};
+
+use jsonrpc_core::MetaIoHandler;
+
+const CONTENT_LENGTH_HEADER: &str = "Content-Length:";
+
+/// Frames larger than this are rejected outright: `Content-Length` is attacker-controlled (it
+/// comes straight off the wire before we've parsed anything), so we must not allocate a buffer
+/// of whatever size a peer claims before reading a single body byte.
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// A single header line longer than this (or a header block with more lines than
+/// `MAX_HEADER_LINES`) is rejected before it is fully buffered: the same attacker-controlled-size
+/// concern as `MAX_FRAME_LEN` applies to `read_line`'s `String`, which is otherwise unbounded.
+const MAX_HEADER_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADER_LINES: usize = 64;
+
+/// Reads a single `Content-Length`-prefixed message off `reader`. Returns `Ok(None)` on a clean
+/// EOF (the peer closed its end) before any header was read.
+fn read_framed_message<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut content_length: Option<usize> = None;
+    for i in 0..MAX_HEADER_LINES {
+        let mut line = String::new();
+        let read = reader.by_ref().take(MAX_HEADER_LINE_LEN).read_line(&mut line)?;
+        if read == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed in the middle of a header block",
+            ));
+        }
+        if !line.ends_with('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "header line exceeds the {} bytes limit or is missing its terminator",
+                    MAX_HEADER_LINE_LEN
+                ),
+            ));
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            // End of the header block.
+            let content_length = content_length.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+            })?;
+            if content_length > MAX_FRAME_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Content-Length {} exceeds the {} bytes limit",
+                        content_length, MAX_FRAME_LEN
+                    ),
+                ));
+            }
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body)?;
+            return String::from_utf8(body)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+
+        if let Some(value) = line.strip_prefix(CONTENT_LENGTH_HEADER) {
+            content_length = Some(value.trim().parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid Content-Length header: '{}'", value),
+                )
+            })?);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("header block exceeds {} lines", MAX_HEADER_LINES),
+    ))
+}
+
+/// Writes `body` to `writer`, framed with its `Content-Length` header.
+fn write_framed_message<W: Write>(writer: &mut W, body: &str) -> io::Result<()> {
+    write!(
+        writer,
+        "{} {}\r\n\r\n{}",
+        CONTENT_LENGTH_HEADER,
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
+
+/// A handle for pushing an already-serialized message (a response or a pub/sub notification) to
+/// a framed transport's writer task, out of band from the reader loop.
+#[derive(Clone)]
+pub struct FramedSender(mpsc::Sender<String>);
+
+impl FramedSender {
+    pub fn send(&self, message: String) -> Result<(), mpsc::SendError<String>> {
+        self.0.send(message)
+    }
+}
+
+/// Spawns the reader and writer tasks for a `Content-Length`-framed `reader`/`writer` pair (a
+/// child process' stdin/stdout, or a length-delimited Unix socket connection) and returns a
+/// [`FramedSender`] for pushing out-of-band notifications to the peer. Each incoming request is
+/// handled on its own thread so a slow request doesn't hold up framing of the next one; the
+/// writer task serializes everything (responses and notifications alike) in the order it
+/// receives them.
+pub fn serve_framed<R, W>(
+    io: Arc<MetaIoHandler<JsonRpcMetaData>>,
+    meta: JsonRpcMetaData,
+    reader: R,
+    writer: W,
+) -> FramedSender
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let (outgoing_tx, outgoing_rx) = mpsc::channel::<String>();
+
+    thread::spawn(move || {
+        let mut writer = writer;
+        for message in outgoing_rx {
+            if let Err(e) = write_framed_message(&mut writer, &message) {
+                log::error!("Writing framed JSON-RPC message: {:?}", e);
+                break;
+            }
+        }
+    });
+
+    let sender = FramedSender(outgoing_tx);
+    let reader_sender = sender.clone();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        let mut handler_threads: Vec<JoinHandle<()>> = Vec::new();
+        loop {
+            let request = match read_framed_message(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("Reading framed JSON-RPC message: {:?}", e);
+                    break;
+                }
+            };
+
+            let io = Arc::clone(&io);
+            let meta = meta.clone();
+            let sender = reader_sender.clone();
+            handler_threads.push(thread::spawn(move || {
+                if let Some(response) = io.handle_request_sync(&request, meta) {
+                    let _ = sender.send(response);
+                }
+            }));
+            // Reap threads for requests that already finished, so long-running connections
+            // don't accumulate one `JoinHandle` per request forever.
+            handler_threads.retain(|handle| !handle.is_finished());
+        }
+
+        // Wait for every in-flight request to finish queuing its response before this task's
+        // `reader_sender` handle is dropped. The writer task's channel closes once that and
+        // every other `FramedSender` clone (including the one returned to the caller) are gone.
+        for handle in handler_threads {
+            let _ = handle.join();
+        }
+    });
+
+    sender
+}